@@ -0,0 +1,1752 @@
+use crate::data::{InferenceData, InferenceDataFileFormat, Item, Label, PreprocessingPipelineConfig, TextData};
+use crate::tokenization::{Tokenization, TokenizationInfo, TokenizerConfig};
+use crate::utils::py_invalid_type_error;
+use anyhow::anyhow;
+use pyo3::exceptions::PyStopIteration;
+use pyo3::prelude::*;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Items that can be grouped into batches need to know how "big" they are,
+/// e.g. in terms of number of tokens, so that batch limits can be enforced.
+pub trait ItemSize {
+    fn size(&self) -> usize;
+}
+
+/// A source of items, e.g. a file on disk or an in-memory python iterator.
+/// Generators know their minimum length upfront so loaders can report
+/// progress and compute distributed shard sizes without reading ahead.
+pub trait DataGen: Send {
+    type Item;
+
+    fn next(&mut self) -> Option<Self::Item>;
+
+    fn min_len(&self) -> usize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextIterationStrategy {
+    Sequential,
+    Interleaved,
+    Weighted,
+}
+
+impl<'a> FromPyObject<'a> for TextIterationStrategy {
+    fn extract(ob: &'a PyAny) -> PyResult<Self> {
+        let s: String = ob.extract()?;
+        let strategy = match s.as_str() {
+            "sequential" => TextIterationStrategy::Sequential,
+            "interleaved" => TextIterationStrategy::Interleaved,
+            "weighted" => TextIterationStrategy::Weighted,
+            k => return Err(py_invalid_type_error(k, "text iteration strategy")),
+        };
+        Ok(strategy)
+    }
+}
+
+/// A small, deterministic, seedable RNG (xorshift64) used throughout the
+/// loading pipeline wherever randomness must be exactly reproducible and
+/// cheap to checkpoint: the state is a single `u64` that can be read back
+/// out and restored later to continue the exact same stream of draws.
+#[derive(Clone, Copy, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // avoid an all-zero state, which is a fixed point of xorshift
+        Self {
+            state: (seed ^ 0x9E3779B97F4A7C15).max(1),
+        }
+    }
+
+    pub fn from_state(state: u64) -> Self {
+        Self { state: state.max(1) }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    pub fn gen_range(&mut self, n: usize) -> usize {
+        assert!(n > 0, "cannot draw from an empty range");
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// A single source file in a [TextIterationStrategy::Weighted] mixture,
+/// indexed once up front (same seek-based approach as [GlobalShuffleIndex])
+/// so any rank can read the item at a given absolute position directly.
+/// This is what lets [TextIterator] shard a weighted mixture by mixture
+/// position alone: a plain sequential [DataGen], by contrast, would have
+/// every rank's own copy start its cursor back at item 0, so gating *which*
+/// mixture positions a rank consumes would not make the *items* it reads
+/// disjoint from any other rank's.
+struct WeightedSource {
+    path: PathBuf,
+    language: Option<String>,
+    spans: Vec<(u64, u32)>,
+}
+
+impl WeightedSource {
+    fn new(path: &Path, language: Option<String>) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            language,
+            spans: scan_line_offsets(path)?,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    fn read(&self, idx: usize) -> anyhow::Result<TextData> {
+        let &(offset, length) = self.spans.get(idx).ok_or_else(|| {
+            anyhow!(
+                "item {idx} out of range for weighted source {}",
+                self.path.display()
+            )
+        })?;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; length as usize];
+        file.read_exact(&mut buf)?;
+        let line = String::from_utf8(buf)?;
+        Ok(TextData::new(line, None, self.language.clone()))
+    }
+}
+
+/// Draws items from one or more [DataGen]s according to a [TextIterationStrategy].
+pub struct TextIterator {
+    gens: Vec<Box<dyn DataGen<Item = anyhow::Result<TextData>>>>,
+    // only populated for Weighted, built and consumed via direct, indexed
+    // reads instead of `gens`, see [WeightedSource]
+    weighted_sources: Vec<WeightedSource>,
+    strategy: TextIterationStrategy,
+    seed: u64,
+    next_idx: usize,
+    rank: usize,
+    world_size: usize,
+    // position of the next draw in the (rank-independent) mixture stream,
+    // used to derive a source pick deterministically from (seed, mix_idx)
+    // instead of from a mutable rng whose state would depend on call order
+    mix_idx: usize,
+    // each source's fixed original size, captured once at construction time
+    // so mixture weights don't drift as ranks consume their own generators
+    // at different rates; only meaningful for Weighted, where an entry is
+    // removed alongside its exhausted source to stay index-aligned with
+    // `weighted_sources`
+    weights: Vec<usize>,
+    // next absolute item index to read from each weighted source, advanced
+    // for *every* mixture position that source is drawn for, regardless of
+    // whether this rank owns that position (see `next()`). Because all
+    // ranks derive the same sequence of source picks from (seed, mix_idx),
+    // this stays in lock-step across ranks without any communication, and
+    // since a rank only ever reads at the position when it does own the
+    // draw, each rank ends up reading a disjoint set of absolute indices.
+    source_pos: Vec<usize>,
+}
+
+impl TextIterator {
+    pub fn new(
+        gens: Vec<Box<dyn DataGen<Item = anyhow::Result<TextData>>>>,
+        strategy: TextIterationStrategy,
+        seed: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        assert_ne!(
+            strategy,
+            TextIterationStrategy::Weighted,
+            "use new_weighted_distributed for Weighted, its sources need \
+            indexed, seek-based access rather than a plain DataGen"
+        );
+        if gens.is_empty() {
+            return Err(anyhow!("expected at least one generator"));
+        }
+        Ok(Self {
+            gens,
+            weighted_sources: Vec::new(),
+            strategy,
+            seed: seed.unwrap_or(0),
+            next_idx: 0,
+            rank: 0,
+            world_size: 1,
+            mix_idx: 0,
+            weights: Vec::new(),
+            source_pos: Vec::new(),
+        })
+    }
+
+    /// Builds a [TextIterationStrategy::Weighted] iterator over `sources`
+    /// (file path, language) pairs that shards the mixture across
+    /// `world_size` ranks internally: every rank derives the identical
+    /// sequence of source draws from `(seed, mix_idx)` alone, and each
+    /// materializes only the draws assigned to it (`mix_idx % world_size ==
+    /// rank`), reading each source at the absolute position its draw
+    /// corresponds to in the (rank-independent) global draw order for that
+    /// source. This keeps the realized mixture ratios exactly reproducible
+    /// no matter how many ranks are reading it, and, unlike sharding the
+    /// merged output stream after the fact, never has two ranks read the
+    /// same item.
+    pub fn new_weighted_distributed(
+        sources: Vec<(PathBuf, Option<String>)>,
+        seed: Option<u64>,
+        rank: usize,
+        world_size: usize,
+    ) -> anyhow::Result<Self> {
+        if sources.is_empty() {
+            return Err(anyhow!("expected at least one generator"));
+        }
+        assert!(
+            rank < world_size,
+            "rank {rank} is invalid given world size {world_size}"
+        );
+        let weighted_sources: Vec<WeightedSource> = sources
+            .into_iter()
+            .map(|(path, language)| WeightedSource::new(&path, language))
+            .collect::<anyhow::Result<_>>()?;
+        let weights: Vec<usize> = weighted_sources.iter().map(|s| s.len().max(1)).collect();
+        let source_pos = vec![0; weighted_sources.len()];
+        Ok(Self {
+            gens: Vec::new(),
+            weighted_sources,
+            strategy: TextIterationStrategy::Weighted,
+            seed: seed.unwrap_or(0),
+            next_idx: 0,
+            rank,
+            world_size,
+            mix_idx: 0,
+            weights,
+            source_pos,
+        })
+    }
+
+    pub fn min_len(&self) -> usize {
+        if self.strategy == TextIterationStrategy::Weighted {
+            self.weighted_sources.iter().map(|s| s.len()).sum()
+        } else {
+            self.gens.iter().map(|g| g.min_len()).sum()
+        }
+    }
+
+    /// Derives the source index for mixture position `mix_idx` purely from
+    /// `(seed, mix_idx)`, so the same position always draws from the same
+    /// source regardless of rank, world size, or draw order.
+    fn weighted_gen_idx(&self, mix_idx: usize) -> usize {
+        let total: usize = self.weights.iter().sum();
+        let mut rng = Rng::new(self.seed ^ (mix_idx as u64).wrapping_mul(0x9E3779B97F4A7C15));
+        let mut draw = rng.gen_range(total);
+        for (idx, w) in self.weights.iter().enumerate() {
+            if draw < *w {
+                return idx;
+            }
+            draw -= w;
+        }
+        self.weights.len() - 1
+    }
+
+    fn next_gen_idx(&mut self) -> Option<usize> {
+        match self.strategy {
+            TextIterationStrategy::Sequential => {
+                (!self.gens.is_empty()).then(|| self.next_idx.min(self.gens.len() - 1))
+            }
+            TextIterationStrategy::Interleaved => {
+                (!self.gens.is_empty()).then(|| self.next_idx % self.gens.len())
+            }
+            TextIterationStrategy::Weighted => {
+                (!self.weighted_sources.is_empty()).then(|| self.weighted_gen_idx(self.mix_idx))
+            }
+        }
+    }
+}
+
+impl Iterator for TextIterator {
+    type Item = anyhow::Result<TextData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let idx = self.next_gen_idx()?;
+            if self.strategy == TextIterationStrategy::Sequential {
+                match self.gens[idx].next() {
+                    Some(item) => return Some(item),
+                    None => {
+                        if idx + 1 >= self.gens.len() {
+                            return None;
+                        }
+                        self.next_idx = idx + 1;
+                        continue;
+                    }
+                }
+            } else if self.strategy == TextIterationStrategy::Weighted {
+                let mix_idx = self.mix_idx;
+                self.mix_idx = self.mix_idx.wrapping_add(1);
+                let pos = self.source_pos[idx];
+                if pos >= self.weighted_sources[idx].len() {
+                    // this source is exhausted, drop it and retry; every rank
+                    // sees the same cursor here, so every rank drops it at the
+                    // same mixture position and the ranks stay in lock-step
+                    self.weighted_sources.remove(idx);
+                    self.weights.remove(idx);
+                    self.source_pos.remove(idx);
+                    if self.weighted_sources.is_empty() {
+                        return None;
+                    }
+                    continue;
+                }
+                // advance this source's global read cursor for every rank,
+                // whether or not it owns this mixture position, so the
+                // position it reads at when it does own a draw is the true
+                // cumulative count of draws of this source so far
+                self.source_pos[idx] += 1;
+                if mix_idx % self.world_size != self.rank {
+                    // not this rank's turn for this position in the mixture
+                    // stream; skip without reading so this rank only ever
+                    // reads the absolute positions assigned to it
+                    continue;
+                }
+                return Some(self.weighted_sources[idx].read(pos));
+            } else {
+                self.next_idx = self.next_idx.wrapping_add(1);
+                if let Some(item) = self.gens[idx].next() {
+                    return Some(item);
+                }
+                // this generator is exhausted, drop it and retry
+                self.gens.remove(idx);
+                if self.gens.is_empty() {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// A [DataGen] over a single file that reads only the byte ranges assigned
+/// to `rank`, determined up front from a one-time line-offset scan of the
+/// file. Unlike reading the whole file and discarding `world_size - 1` of
+/// every `world_size` items downstream, every rank here only reads back and
+/// decodes the ranges it actually needs, so decoding and pipeline work scale
+/// with the rank's own share. The offset scan itself still reads through the
+/// whole file on every rank.
+struct ShardedFileGen {
+    path: PathBuf,
+    language: Option<String>,
+    spans: Vec<(u64, u32)>,
+    pos: usize,
+}
+
+impl ShardedFileGen {
+    fn new(
+        path: &Path,
+        min_length: Option<usize>,
+        language: Option<String>,
+        rank: usize,
+        world_size: usize,
+    ) -> anyhow::Result<Self> {
+        let min_length = min_length.unwrap_or(0);
+        let spans: Vec<(u64, u32)> = scan_line_offsets(path)?
+            .into_iter()
+            .filter(|&(_, length)| length as usize >= min_length)
+            .enumerate()
+            .filter_map(|(idx, span)| (idx % world_size == rank).then_some(span))
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            language,
+            spans,
+            pos: 0,
+        })
+    }
+
+    fn read_span(&self, offset: u64, length: u32) -> anyhow::Result<TextData> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; length as usize];
+        file.read_exact(&mut buf)?;
+        let line = String::from_utf8(buf)?;
+        Ok(TextData::new(line, None, self.language.clone()))
+    }
+}
+
+impl DataGen for ShardedFileGen {
+    type Item = anyhow::Result<TextData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let &(offset, length) = self.spans.get(self.pos)?;
+        self.pos += 1;
+        Some(self.read_span(offset, length))
+    }
+
+    fn min_len(&self) -> usize {
+        self.spans.len().saturating_sub(self.pos)
+    }
+}
+
+/// Builds a generator over `path` that only reads the items assigned to
+/// `rank` out of `world_size`, seeking directly to each one's byte offset.
+/// See [ShardedFileGen].
+pub fn text_data_generator_from_files(
+    path: &Path,
+    min_length: Option<usize>,
+    language: Option<String>,
+    rank: usize,
+    world_size: usize,
+) -> anyhow::Result<Box<dyn DataGen<Item = anyhow::Result<TextData>>>> {
+    Ok(Box::new(ShardedFileGen::new(
+        path,
+        min_length,
+        language,
+        rank,
+        world_size,
+    )?))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShuffleMode {
+    Local,
+    Global,
+}
+
+impl<'a> FromPyObject<'a> for ShuffleMode {
+    fn extract(ob: &'a PyAny) -> PyResult<Self> {
+        let s: String = ob.extract()?;
+        let mode = match s.as_str() {
+            "local" => ShuffleMode::Local,
+            "global" => ShuffleMode::Global,
+            k => return Err(py_invalid_type_error(k, "shuffle mode")),
+        };
+        Ok(mode)
+    }
+}
+
+/// Draws a uniformly random permutation of `0..n`, seeded so the same
+/// `seed` always produces the same permutation.
+pub fn fisher_yates_permutation(n: usize, seed: u64) -> Vec<usize> {
+    let mut perm: Vec<usize> = (0..n).collect();
+    if n <= 1 {
+        return perm;
+    }
+    let mut rng = Rng::new(seed);
+    for i in (1..n).rev() {
+        let j = rng.gen_range(i + 1);
+        perm.swap(i, j);
+    }
+    perm
+}
+
+/// Scans a single file for newline-delimited items, returning each one's
+/// `(offset, length)` in bytes (trailing `\r\n`/`\n` stripped from length).
+/// Used to build direct-seek indices so items can be read back in any order,
+/// or by rank, without a full linear scan of the file's contents.
+fn scan_line_offsets(path: &Path) -> anyhow::Result<Vec<(u64, u32)>> {
+    let file =
+        File::open(path).map_err(|e| anyhow!("failed to open {}: {e}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut offset: u64 = 0;
+    let mut spans = vec![];
+    loop {
+        let mut line = Vec::new();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        let mut length = read as u32;
+        while length > 0 && matches!(line[length as usize - 1], b'\n' | b'\r') {
+            length -= 1;
+        }
+        if length > 0 {
+            spans.push((offset, length));
+        }
+        offset += read as u64;
+    }
+    Ok(spans)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ItemSpan {
+    file_id: u32,
+    offset: u64,
+    length: u32,
+}
+
+/// A lightweight on-disk index over every item (line) across a set of
+/// files, recording where each one lives as a `(file_id, offset, length)`
+/// triple. Building the index requires a single scan of the inputs; after
+/// that, items can be read back in any order via a direct seek, which is
+/// what makes [ShuffleMode::Global] affordable.
+pub struct GlobalShuffleIndex {
+    files: Vec<PathBuf>,
+    languages: Vec<Option<String>>,
+    items: Vec<ItemSpan>,
+}
+
+impl GlobalShuffleIndex {
+    /// Builds the index, or loads it from `cache_dir` if a valid cache
+    /// (keyed by the file paths, sizes, and modification times) already
+    /// exists there.
+    pub fn build_or_load(
+        files: Vec<PathBuf>,
+        languages: Vec<Option<String>>,
+        cache_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        fs::create_dir_all(cache_dir)?;
+        let cache_path = cache_dir.join(format!("{:016x}.idx", Self::cache_key(&files)));
+        let items = if cache_path.exists() {
+            Self::load(&cache_path)?
+        } else {
+            let items = Self::scan(&files)?;
+            Self::save(&cache_path, &items)?;
+            items
+        };
+        Ok(Self {
+            files,
+            languages,
+            items,
+        })
+    }
+
+    fn cache_key(files: &[PathBuf]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for file in files {
+            file.hash(&mut hasher);
+            if let Ok(meta) = fs::metadata(file) {
+                meta.len().hash(&mut hasher);
+                if let Ok(modified) = meta.modified() {
+                    modified.hash(&mut hasher);
+                }
+            }
+        }
+        hasher.finish()
+    }
+
+    fn scan(files: &[PathBuf]) -> anyhow::Result<Vec<ItemSpan>> {
+        let mut items = vec![];
+        for (file_id, path) in files.iter().enumerate() {
+            for (offset, length) in scan_line_offsets(path)? {
+                items.push(ItemSpan {
+                    file_id: file_id as u32,
+                    offset,
+                    length,
+                });
+            }
+        }
+        Ok(items)
+    }
+
+    fn save(path: &Path, items: &[ItemSpan]) -> anyhow::Result<()> {
+        let mut buf = Vec::with_capacity(items.len() * 16);
+        for item in items {
+            buf.extend_from_slice(&item.file_id.to_le_bytes());
+            buf.extend_from_slice(&item.offset.to_le_bytes());
+            buf.extend_from_slice(&item.length.to_le_bytes());
+        }
+        fs::write(path, buf)?;
+        Ok(())
+    }
+
+    fn load(path: &Path) -> anyhow::Result<Vec<ItemSpan>> {
+        let buf = fs::read(path)?;
+        if buf.len() % 16 != 0 {
+            return Err(anyhow!(
+                "corrupt global shuffle index cache at {}",
+                path.display()
+            ));
+        }
+        Ok(buf
+            .chunks_exact(16)
+            .map(|c| ItemSpan {
+                file_id: u32::from_le_bytes(c[0..4].try_into().unwrap()),
+                offset: u64::from_le_bytes(c[4..12].try_into().unwrap()),
+                length: u32::from_le_bytes(c[12..16].try_into().unwrap()),
+            })
+            .collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The item positions assigned to `rank`, drawn from a uniform
+    /// permutation of all items keyed by `seed + epoch` and sharded by
+    /// taking every `world_size`-th permuted position starting at `rank`.
+    pub fn shard_permutation(
+        &self,
+        seed: u64,
+        epoch: u64,
+        rank: usize,
+        world_size: usize,
+    ) -> Vec<usize> {
+        fisher_yates_permutation(self.items.len(), seed.wrapping_add(epoch))
+            .into_iter()
+            .enumerate()
+            .filter_map(|(pos, idx)| (pos % world_size == rank).then_some(idx))
+            .collect()
+    }
+
+    fn read_item(&self, idx: usize) -> anyhow::Result<TextData> {
+        let span = &self.items[idx];
+        let path = &self.files[span.file_id as usize];
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(span.offset))?;
+        let mut buf = vec![0u8; span.length as usize];
+        file.read_exact(&mut buf)?;
+        let line = String::from_utf8(buf)?;
+        Ok(TextData::new(
+            line,
+            None,
+            self.languages[span.file_id as usize].clone(),
+        ))
+    }
+}
+
+/// A [DataGen] that streams items from a [GlobalShuffleIndex] in the exact
+/// order given by its precomputed, rank-sharded permutation, seeking
+/// directly to each item's offset rather than scanning the files linearly.
+pub struct GlobalShuffleGen {
+    index: Arc<GlobalShuffleIndex>,
+    order: Vec<usize>,
+    pos: usize,
+}
+
+impl GlobalShuffleGen {
+    pub fn new(
+        index: Arc<GlobalShuffleIndex>,
+        seed: u64,
+        epoch: u64,
+        rank: usize,
+        world_size: usize,
+    ) -> Self {
+        let order = index.shard_permutation(seed, epoch, rank, world_size);
+        Self {
+            index,
+            order,
+            pos: 0,
+        }
+    }
+}
+
+impl DataGen for GlobalShuffleGen {
+    type Item = anyhow::Result<TextData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx = *self.order.get(self.pos)?;
+        self.pos += 1;
+        Some(self.index.read_item(idx))
+    }
+
+    fn min_len(&self) -> usize {
+        self.order.len().saturating_sub(self.pos)
+    }
+}
+
+/// A [DataGen] that eagerly parses every line of a file into [InferenceData]
+/// up front, according to `file_format`. Lines that don't carry their own
+/// language (formats without a language column) fall back to `language`.
+struct InferenceFileGen {
+    items: Vec<InferenceData>,
+    pos: usize,
+}
+
+impl InferenceFileGen {
+    fn new(
+        path: &Path,
+        file_format: InferenceDataFileFormat,
+        language: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let mut items = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let mut data = InferenceData::from_str(&line?, &file_format);
+            if data.language.is_none() {
+                data.language = language.clone();
+            }
+            items.push(data);
+        }
+        Ok(Self { items, pos: 0 })
+    }
+}
+
+impl DataGen for InferenceFileGen {
+    type Item = anyhow::Result<InferenceData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.items.get(self.pos)?.clone();
+        self.pos += 1;
+        Some(Ok(item))
+    }
+
+    fn min_len(&self) -> usize {
+        self.items.len().saturating_sub(self.pos)
+    }
+}
+
+pub fn inference_data_generator_from_file(
+    path: &Path,
+    file_format: InferenceDataFileFormat,
+    language: Option<String>,
+) -> anyhow::Result<Box<dyn DataGen<Item = anyhow::Result<InferenceData>>>> {
+    Ok(Box::new(InferenceFileGen::new(path, file_format, language)?))
+}
+
+/// A [DataGen] that pulls items one at a time from a Python iterator via its
+/// `__next__` method, stopping on `StopIteration` like a normal Python loop
+/// would. Its length isn't known upfront, unlike the file-backed generators.
+struct InferencePythonGen {
+    iterator: PyObject,
+}
+
+impl DataGen for InferencePythonGen {
+    type Item = anyhow::Result<InferenceData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Python::with_gil(|py| {
+            let iter = self.iterator.as_ref(py);
+            match iter.call_method0("__next__") {
+                Ok(item) => Some(item.extract::<InferenceData>().map_err(|e| {
+                    anyhow!("failed to extract InferenceData from python iterator: {e}")
+                })),
+                Err(e) if e.is_instance_of::<PyStopIteration>(py) => None,
+                Err(e) => Some(Err(anyhow!("python iterator raised an error: {e}"))),
+            }
+        })
+    }
+
+    fn min_len(&self) -> usize {
+        0
+    }
+}
+
+pub fn inference_data_generator_from_python(
+    iterator: PyObject,
+) -> Box<dyn DataGen<Item = anyhow::Result<InferenceData>>> {
+    Box::new(InferencePythonGen { iterator })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchLimitType {
+    BatchSize,
+    PaddedSize,
+    /// Greedily packs items into sequences of at most `batch_limit` tokens
+    /// each instead of padding every item to the longest one in the batch;
+    /// the packed rows are padded to the longest row. See
+    /// [Batched::take_packed_batch].
+    Packed,
+}
+
+impl<'a> FromPyObject<'a> for BatchLimitType {
+    fn extract(ob: &'a PyAny) -> PyResult<Self> {
+        let s: String = ob.extract()?;
+        let limit_type = match s.as_str() {
+            "batch_size" => BatchLimitType::BatchSize,
+            "padded_size" => BatchLimitType::PaddedSize,
+            "packed" => BatchLimitType::Packed,
+            k => return Err(py_invalid_type_error(k, "batch limit type")),
+        };
+        Ok(limit_type)
+    }
+}
+
+/// Snapshot of everything a [Batched] iterator needs to resume drawing
+/// batches exactly where it left off: how many items it has already
+/// yielded in the current epoch (used to recompute `fast_forward`) and,
+/// when shuffling, the RNG state driving the shuffle buffer.
+///
+/// When `sortish` is enabled, a whole megabatch is pulled from the source,
+/// sorted, split into batches and served in shuffled order, so the batches
+/// served so far are not a prefix of the source in original order — only
+/// once every batch of a megabatch has been served is `fast_forward`-ing by
+/// `items_yielded` guaranteed to land on the same remaining items. To keep
+/// resume exact, `items_yielded` is only advanced in whole-megabatch steps
+/// in that case, i.e. it lags behind the true number of items handed out
+/// until the megabatch they came from has been fully drained.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoaderState {
+    pub items_yielded: usize,
+    pub rng_state: Option<u64>,
+}
+
+pub struct Batched<I: Iterator> {
+    inner: I,
+    buffer: Vec<I::Item>,
+    sort: bool,
+    sortish: bool,
+    sortish_queue: Vec<Vec<I::Item>>,
+    // items already yielded from the current, not yet fully drained
+    // megabatch; folded into state.items_yielded only once that megabatch
+    // empties out, so resume always lands on a megabatch boundary
+    sortish_pending: usize,
+    // ready-made packed (items, row_sizes) batches waiting to be yielded;
+    // an item too long to share a row with anything else (size > batch_limit)
+    // gets flushed here as its own single-row batch, so it never forces the
+    // rest of the rows sharing a batch's tensor to pad up to its length
+    packed_queue: Vec<(Vec<I::Item>, Vec<usize>)>,
+    shuffle: bool,
+    prefetch_factor: usize,
+    batch_limit: usize,
+    batch_limit_type: BatchLimitType,
+    rng: Rng,
+    state: Arc<Mutex<LoaderState>>,
+}
+
+impl<I> Batched<I>
+where
+    I: Iterator,
+    I::Item: ItemSize + Clone,
+{
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        inner: I,
+        sort: bool,
+        sortish: bool,
+        shuffle: bool,
+        prefetch_factor: usize,
+        batch_limit: usize,
+        batch_limit_type: BatchLimitType,
+        seed: Option<u64>,
+    ) -> Self {
+        let rng = Rng::new(seed.unwrap_or(0));
+        Self {
+            inner,
+            buffer: Vec::new(),
+            sort,
+            sortish,
+            sortish_queue: Vec::new(),
+            sortish_pending: 0,
+            packed_queue: Vec::new(),
+            shuffle,
+            prefetch_factor,
+            batch_limit,
+            batch_limit_type,
+            rng,
+            state: Arc::new(Mutex::new(LoaderState::default())),
+        }
+    }
+
+    /// A handle to the (epoch-scoped) loader state shared with whoever
+    /// built this iterator, so it can be read out for checkpointing even
+    /// after `self` has been boxed into an opaque `dyn Iterator`.
+    pub fn state_handle(&self) -> Arc<Mutex<LoaderState>> {
+        self.state.clone()
+    }
+
+    /// Resumes the shuffle RNG from a previously saved state, so the
+    /// sequence of draws continues exactly where `state_dict()` left off.
+    pub fn resume_rng(&mut self, rng_state: u64) {
+        self.rng = Rng::from_state(rng_state);
+    }
+
+    fn item_len(&self, item: &I::Item) -> usize {
+        match self.batch_limit_type {
+            BatchLimitType::BatchSize => 1,
+            BatchLimitType::PaddedSize | BatchLimitType::Packed => item.size(),
+        }
+    }
+
+    fn fill_buffer(&mut self) {
+        let target = self.batch_limit * self.prefetch_factor.max(1);
+        while self.buffer.len() < target {
+            match self.inner.next() {
+                Some(item) => self.buffer.push(item),
+                None => break,
+            }
+        }
+        if self.sort {
+            self.buffer.sort_by_key(|item| item.size());
+        }
+    }
+
+    fn take_batch(&mut self) -> Vec<I::Item> {
+        let mut batch = Vec::new();
+        let mut total = 0usize;
+        while total < self.batch_limit && !self.buffer.is_empty() {
+            let idx = if self.shuffle {
+                self.rng.gen_range(self.buffer.len())
+            } else {
+                0
+            };
+            let item = self.buffer.remove(idx);
+            total += self.item_len(&item).max(1);
+            batch.push(item);
+        }
+        batch
+    }
+
+    /// Greedily bin-packs the prefetch buffer into sequences of at most
+    /// `batch_limit` tokens each, first-fit: an item goes into the first row
+    /// with enough remaining room, or starts a new row if none has. All the
+    /// resulting rows share a single tensor, so they're returned together as
+    /// one (items, row_sizes) batch, padded only up to the longest row.
+    ///
+    /// An item whose own size already exceeds `batch_limit` can never share
+    /// a row with anything else, and if it shared a batch with normally-sized
+    /// rows, every one of them would have to pad up to its size. So it is
+    /// instead pushed to `packed_queue` as its own single-row batch, kept
+    /// separate from the batch built out of the remaining, normally-sized
+    /// items.
+    fn take_packed_batch(&mut self) -> (Vec<I::Item>, Vec<usize>) {
+        let mut rows: Vec<(usize, Vec<I::Item>)> = Vec::new();
+        let drained: Vec<I::Item> = self.buffer.drain(..).collect();
+        for item in drained {
+            let len = self.item_len(&item).max(1);
+            if len > self.batch_limit {
+                self.packed_queue.push((vec![item], vec![1]));
+                continue;
+            }
+            match rows.iter_mut().find(|(used, _)| used + len <= self.batch_limit) {
+                Some((used, row)) => {
+                    *used += len;
+                    row.push(item);
+                }
+                None => rows.push((len, vec![item])),
+            }
+        }
+        let mut items = Vec::new();
+        let mut row_sizes = Vec::with_capacity(rows.len());
+        for (_, row) in rows {
+            row_sizes.push(row.len());
+            items.extend(row);
+        }
+        (items, row_sizes)
+    }
+
+    /// Fills a megabatch of `prefetch_factor * batch_limit` items, sorts it
+    /// by length for padding efficiency, splits it into consecutive batches,
+    /// then shuffles the order of those batches so training still sees a
+    /// stochastic sequence of batches across epochs. This gives near-sorted
+    /// (hence "sortish") batches without fully destroying randomness the way
+    /// a plain `sort` would.
+    fn fill_sortish_queue(&mut self) {
+        // only called once the previous megabatch's queue has fully drained,
+        // so everything yielded from it is now a safe, exact resume point
+        let mut state = self.state.lock().unwrap();
+        state.items_yielded += self.sortish_pending;
+        drop(state);
+        self.sortish_pending = 0;
+        let target = self.batch_limit * self.prefetch_factor.max(1);
+        while self.buffer.len() < target {
+            match self.inner.next() {
+                Some(item) => self.buffer.push(item),
+                None => break,
+            }
+        }
+        if self.buffer.is_empty() {
+            return;
+        }
+        self.buffer.sort_by_key(|item| item.size());
+        let drained: Vec<I::Item> = self.buffer.drain(..).collect();
+        let mut items = drained.into_iter().peekable();
+        let mut batches: Vec<Vec<I::Item>> = Vec::new();
+        while items.peek().is_some() {
+            let mut batch = Vec::new();
+            let mut total = 0usize;
+            while total < self.batch_limit {
+                let Some(item) = items.next() else {
+                    break;
+                };
+                total += self.item_len(&item).max(1);
+                batch.push(item);
+            }
+            batches.push(batch);
+        }
+        // shuffle the order of the pre-split batches in place
+        for i in (1..batches.len()).rev() {
+            let j = self.rng.gen_range(i + 1);
+            batches.swap(i, j);
+        }
+        self.sortish_queue = batches;
+    }
+}
+
+impl<I> Iterator for Batched<I>
+where
+    I: Iterator,
+    I::Item: ItemSize + Clone,
+{
+    type Item = crate::data::Batch<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let batch = if self.sortish && self.batch_limit_type != BatchLimitType::Packed {
+            if self.sortish_queue.is_empty() {
+                self.fill_sortish_queue();
+            }
+            if self.sortish_queue.is_empty() {
+                return None;
+            }
+            crate::data::Batch::new(self.sortish_queue.remove(0))
+        } else if self.batch_limit_type == BatchLimitType::Packed {
+            if self.packed_queue.is_empty() {
+                self.fill_buffer();
+                if self.buffer.is_empty() {
+                    return None;
+                }
+                let (items, row_sizes) = self.take_packed_batch();
+                if !items.is_empty() {
+                    self.packed_queue.push((items, row_sizes));
+                }
+            }
+            if self.packed_queue.is_empty() {
+                return None;
+            }
+            let (items, row_sizes) = self.packed_queue.remove(0);
+            crate::data::Batch::new_packed(items, row_sizes)
+        } else {
+            self.fill_buffer();
+            if self.buffer.is_empty() {
+                return None;
+            }
+            crate::data::Batch::new(self.take_batch())
+        };
+        let mut state = self.state.lock().unwrap();
+        if self.sortish && self.batch_limit_type != BatchLimitType::Packed {
+            // folded into state.items_yielded only once this megabatch
+            // fully drains, see the LoaderState docs
+            self.sortish_pending += batch.len();
+            if self.sortish_queue.is_empty() {
+                state.items_yielded += self.sortish_pending;
+                self.sortish_pending = 0;
+            }
+        } else {
+            state.items_yielded += batch.len();
+        }
+        state.rng_state = (self.shuffle || self.sortish).then(|| self.rng.state());
+        drop(state);
+        Some(batch)
+    }
+}
+
+pub trait BatchedIterator: Iterator + Sized
+where
+    Self::Item: ItemSize + Clone,
+{
+    #[allow(clippy::too_many_arguments)]
+    fn batched(
+        self,
+        sort: bool,
+        sortish: bool,
+        shuffle: bool,
+        prefetch_factor: usize,
+        batch_limit: usize,
+        batch_limit_type: BatchLimitType,
+        seed: Option<u64>,
+    ) -> Batched<Self> {
+        Batched::new(
+            self,
+            sort,
+            sortish,
+            shuffle,
+            prefetch_factor,
+            batch_limit,
+            batch_limit_type,
+            seed,
+        )
+    }
+}
+
+impl<I> BatchedIterator for I
+where
+    I: Iterator,
+    I::Item: ItemSize + Clone,
+{
+}
+
+pub trait Tensorize {
+    type Output;
+
+    fn tensorize(&self, tokenizer: &crate::tokenization::Tokenizer) -> Self::Output;
+}
+
+pub struct Tensorized<I> {
+    inner: I,
+    tokenizer: crate::tokenization::Tokenizer,
+}
+
+impl<I, T> Iterator for Tensorized<I>
+where
+    I: Iterator<Item = crate::data::Batch<T>>,
+    crate::data::Batch<T>: Tensorize,
+{
+    type Item = (
+        crate::data::Batch<T>,
+        <crate::data::Batch<T> as Tensorize>::Output,
+    );
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let batch = self.inner.next()?;
+        let tensorized = batch.tensorize(&self.tokenizer);
+        Some((batch, tensorized))
+    }
+}
+
+pub trait TensorizedIterator: Iterator + Sized {
+    fn tensorized(
+        self,
+        tokenizer_config: crate::tokenization::TokenizerConfig,
+    ) -> Tensorized<Self> {
+        Tensorized {
+            inner: self,
+            tokenizer: crate::tokenization::tokenizer(tokenizer_config),
+        }
+    }
+}
+
+impl<I: Iterator> TensorizedIterator for I {}
+
+pub trait PipelineIterator: Iterator + Sized {
+    /// Applies `pipeline` to every item in order on the consuming thread;
+    /// `num_threads` is not used, so preprocessing here is single-threaded.
+    fn pipe<O: Send + 'static>(
+        self,
+        pipeline: &crate::data::Pipeline<Self::Item, O>,
+        num_threads: u8,
+        seed: Option<u64>,
+    ) -> Box<dyn Iterator<Item = O> + Send>
+    where
+        Self: Send + 'static,
+        Self::Item: Send + 'static,
+    {
+        let pipeline = pipeline.clone();
+        let _ = num_threads;
+        Box::new(
+            self.enumerate()
+                .map(move |(idx, item)| pipeline.apply(item, idx, seed)),
+        )
+    }
+}
+
+impl<I: Iterator> PipelineIterator for I {}
+
+/// Pulls items from `inner` on a background thread into a bounded channel
+/// of size `buffer_size`, so downstream consumption overlaps with upstream
+/// I/O and preprocessing instead of waiting on it synchronously.
+pub struct Buffered<T> {
+    receiver: std::sync::mpsc::Receiver<T>,
+}
+
+pub trait BufferedIterator: Iterator + Sized {
+    fn buffered(self, buffer_size: usize) -> Buffered<Self::Item>
+    where
+        Self: Send + 'static,
+        Self::Item: Send + 'static,
+    {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(buffer_size.max(1));
+        std::thread::spawn(move || {
+            for item in self {
+                if sender.send(item).is_err() {
+                    break;
+                }
+            }
+        });
+        Buffered { receiver }
+    }
+}
+
+impl<I: Iterator> BufferedIterator for I {}
+
+impl<T> Iterator for Buffered<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_i32_vec(buf: &mut Vec<u8>, v: &[i32]) {
+    buf.extend_from_slice(&(v.len() as u32).to_le_bytes());
+    for x in v {
+        buf.extend_from_slice(&x.to_le_bytes());
+    }
+}
+
+fn read_string(buf: &[u8], cur: &mut usize) -> anyhow::Result<String> {
+    let len = u32::from_le_bytes(buf[*cur..*cur + 4].try_into()?) as usize;
+    *cur += 4;
+    let s = String::from_utf8(buf[*cur..*cur + len].to_vec())?;
+    *cur += len;
+    Ok(s)
+}
+
+fn read_i32_vec(buf: &[u8], cur: &mut usize) -> anyhow::Result<Vec<i32>> {
+    let len = u32::from_le_bytes(buf[*cur..*cur + 4].try_into()?) as usize;
+    *cur += 4;
+    let v = buf[*cur..*cur + len * 4]
+        .chunks_exact(4)
+        .map(|c| i32::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    *cur += len * 4;
+    Ok(v)
+}
+
+struct CacheSpan {
+    token_offset: u64,
+    token_len: u32,
+    original: String,
+    processed: String,
+    language: Option<String>,
+    label: Label,
+}
+
+/// An on-disk "jagged array" cache of already-tokenized [Item]s, keyed by
+/// a hash of the input files plus the preprocessing and tokenizer configs
+/// that produced them: token ids for every item are concatenated into one
+/// data file, and a sidecar index records each item's `(offset, length)`
+/// span into it together with everything else needed to reconstruct the
+/// item without re-running the [crate::data::Pipeline].
+pub struct ItemCache {
+    data_path: PathBuf,
+    records: Vec<CacheSpan>,
+}
+
+impl ItemCache {
+    pub fn cache_key(
+        files: &[PathBuf],
+        pipeline_config: &PreprocessingPipelineConfig,
+        tokenizer_config: &TokenizerConfig,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for file in files {
+            file.hash(&mut hasher);
+            if let Ok(meta) = fs::metadata(file) {
+                meta.len().hash(&mut hasher);
+            }
+        }
+        format!("{pipeline_config:?}").hash(&mut hasher);
+        format!("{tokenizer_config:?}").hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn data_path(cache_dir: &Path, key: u64) -> PathBuf {
+        cache_dir.join(format!("{key:016x}.tokens"))
+    }
+
+    fn tmp_data_path(cache_dir: &Path, key: u64) -> PathBuf {
+        cache_dir.join(format!("{key:016x}.tokens.tmp"))
+    }
+
+    fn index_path(cache_dir: &Path, key: u64) -> PathBuf {
+        cache_dir.join(format!("{key:016x}.index"))
+    }
+
+    /// Loads a previously-built cache for this exact file set and config
+    /// hash, or `None` if no complete cache exists for it yet.
+    pub fn load(cache_dir: &Path, key: u64) -> anyhow::Result<Option<Self>> {
+        let index_path = Self::index_path(cache_dir, key);
+        if !index_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            data_path: Self::data_path(cache_dir, key),
+            records: Self::read_index(&index_path)?,
+        }))
+    }
+
+    fn read_index(path: &Path) -> anyhow::Result<Vec<CacheSpan>> {
+        let buf = fs::read(path)?;
+        let mut cur = 0usize;
+        let mut records = vec![];
+        while cur < buf.len() {
+            let token_offset = u64::from_le_bytes(buf[cur..cur + 8].try_into()?);
+            cur += 8;
+            let token_len = u32::from_le_bytes(buf[cur..cur + 4].try_into()?);
+            cur += 4;
+            let original = read_string(&buf, &mut cur)?;
+            let processed = read_string(&buf, &mut cur)?;
+            let has_lang = buf[cur] != 0;
+            cur += 1;
+            let language = if has_lang {
+                Some(read_string(&buf, &mut cur)?)
+            } else {
+                None
+            };
+            let tag = buf[cur];
+            cur += 1;
+            let label = match tag {
+                0 => {
+                    let label = i32::from_le_bytes(buf[cur..cur + 4].try_into()?);
+                    cur += 4;
+                    Label::Classification(label)
+                }
+                1 => Label::SeqClassification(read_i32_vec(&buf, &mut cur)?),
+                2 => Label::Seq2Seq(read_i32_vec(&buf, &mut cur)?),
+                t => return Err(anyhow!("corrupt item cache index, unknown label tag {t}")),
+            };
+            records.push(CacheSpan {
+                token_offset,
+                token_len,
+                original,
+                processed,
+                language,
+                label,
+            });
+        }
+        Ok(records)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> anyhow::Result<Item> {
+        let record = &self.records[idx];
+        let mut file = File::open(&self.data_path)?;
+        file.seek(SeekFrom::Start(record.token_offset))?;
+        let mut buf = vec![0u8; record.token_len as usize * 4];
+        file.read_exact(&mut buf)?;
+        let token_ids = buf
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        let data = TextData::new(
+            record.original.clone(),
+            Some(record.processed.clone()),
+            record.language.clone(),
+        );
+        let tokenization = Tokenization {
+            token_ids,
+            info: TokenizationInfo::Empty,
+        };
+        Ok(Item::new(data, tokenization, record.label.clone()))
+    }
+}
+
+/// Reads items directly out of an [ItemCache], honoring distributed
+/// sharding the same way the normal (uncached) path does. Visits items in
+/// plain index order by default, or in the order given by `order` (e.g. a
+/// [fisher_yates_permutation], to mirror [ShuffleMode::Global] on a cache
+/// hit the same way [GlobalShuffleGen] does on a miss) when one is given.
+pub struct CachedItemIter {
+    cache: Arc<ItemCache>,
+    order: Option<Vec<usize>>,
+    pos: usize,
+    world_size: usize,
+}
+
+impl CachedItemIter {
+    pub fn new(cache: Arc<ItemCache>, skip: usize, rank: usize, world_size: usize) -> Self {
+        Self {
+            cache,
+            order: None,
+            pos: skip + rank,
+            world_size,
+        }
+    }
+
+    pub fn new_shuffled(
+        cache: Arc<ItemCache>,
+        order: Vec<usize>,
+        skip: usize,
+        rank: usize,
+        world_size: usize,
+    ) -> Self {
+        Self {
+            cache,
+            order: Some(order),
+            pos: skip + rank,
+            world_size,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.order.as_ref().map_or_else(|| self.cache.len(), Vec::len)
+    }
+}
+
+impl Iterator for CachedItemIter {
+    type Item = anyhow::Result<Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.len() {
+            return None;
+        }
+        let idx = self.order.as_ref().map_or(self.pos, |order| order[self.pos]);
+        self.pos += self.world_size.max(1);
+        Some(self.cache.get(idx))
+    }
+}
+
+/// Wraps the normal, pipeline-driven item iterator on a cache miss,
+/// transparently writing every tokenized item to disk as it is produced.
+/// Once the wrapped iterator is exhausted the sidecar index is written
+/// and the data file is atomically renamed into place, so a crash
+/// mid-build never leaves behind a cache that looks complete but isn't.
+pub struct CachingIter<I> {
+    inner: I,
+    data_file: File,
+    data_offset: u64,
+    index_buf: Vec<u8>,
+    tmp_data_path: PathBuf,
+    data_path: PathBuf,
+    index_path: PathBuf,
+    finished: bool,
+}
+
+impl<I> CachingIter<I> {
+    pub fn new(inner: I, cache_dir: &Path, key: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(cache_dir)?;
+        let tmp_data_path = ItemCache::tmp_data_path(cache_dir, key);
+        Ok(Self {
+            inner,
+            data_file: File::create(&tmp_data_path)?,
+            data_offset: 0,
+            index_buf: Vec::new(),
+            tmp_data_path,
+            data_path: ItemCache::data_path(cache_dir, key),
+            index_path: ItemCache::index_path(cache_dir, key),
+            finished: false,
+        })
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        fs::rename(&self.tmp_data_path, &self.data_path)?;
+        fs::write(&self.index_path, &self.index_buf)?;
+        Ok(())
+    }
+}
+
+impl<I> Iterator for CachingIter<I>
+where
+    I: Iterator<Item = Item>,
+{
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(item) = self.inner.next() else {
+            let _ = self.finish();
+            return None;
+        };
+        if self.write_record(&item).is_err() {
+            // a failed write leaves the data file and index out of step, so
+            // mark this cache as never to be finished (renamed and indexed)
+            self.finished = true;
+        }
+        Some(item)
+    }
+}
+
+impl<I> CachingIter<I> {
+    fn write_record(&mut self, item: &Item) -> anyhow::Result<()> {
+        let token_bytes: Vec<u8> = item
+            .tokenization
+            .token_ids
+            .iter()
+            .flat_map(|t| t.to_le_bytes())
+            .collect();
+        self.data_file.write_all(&token_bytes)?;
+        self.index_buf
+            .extend_from_slice(&self.data_offset.to_le_bytes());
+        self.index_buf
+            .extend_from_slice(&(item.tokenization.token_ids.len() as u32).to_le_bytes());
+        write_string(&mut self.index_buf, &item.data.original);
+        write_string(&mut self.index_buf, &item.data.processed);
+        match &item.data.language {
+            Some(lang) => {
+                self.index_buf.push(1);
+                write_string(&mut self.index_buf, lang);
+            }
+            None => self.index_buf.push(0),
+        }
+        match &item.label {
+            Label::Classification(label) => {
+                self.index_buf.push(0);
+                self.index_buf.extend_from_slice(&label.to_le_bytes());
+            }
+            Label::SeqClassification(labels) => {
+                self.index_buf.push(1);
+                write_i32_vec(&mut self.index_buf, labels);
+            }
+            Label::Seq2Seq(labels) => {
+                self.index_buf.push(2);
+                write_i32_vec(&mut self.index_buf, labels);
+            }
+        }
+        self.data_offset += token_bytes.len() as u64;
+        Ok(())
+    }
+}
+
+pub trait CachedIterator: Iterator<Item = Item> + Sized {
+    /// Tees every item through to an on-disk [ItemCache] as it is
+    /// produced, so later epochs can load the cache instead of
+    /// re-running the pipeline. See [CachingIter].
+    fn cached(self, cache_dir: &Path, key: u64) -> anyhow::Result<CachingIter<Self>> {
+        CachingIter::new(self, cache_dir, key)
+    }
+}
+
+impl<I: Iterator<Item = Item>> CachedIterator for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Batch;
+    use crate::tokenization::{Tokenization, TokenizationInfo};
+    use itertools::Itertools;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_fisher_yates_permutation() {
+        assert_eq!(fisher_yates_permutation(0, 0), Vec::<usize>::new());
+        assert_eq!(fisher_yates_permutation(1, 42), vec![0]);
+        let perm = fisher_yates_permutation(100, 22);
+        assert_eq!(perm.iter().sorted().copied().collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+        // same seed must reproduce the same permutation
+        assert_eq!(perm, fisher_yates_permutation(100, 22));
+        // a different seed should (almost certainly) reorder the items
+        assert_ne!(perm, fisher_yates_permutation(100, 23));
+    }
+
+    /// A unique scratch directory under the system temp dir, so tests that
+    /// touch the filesystem don't collide when run concurrently.
+    fn scratch_dir(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("tcu_test_{name}_{}_{n}", std::process::id()))
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct MockItem(usize);
+
+    impl ItemSize for MockItem {
+        fn size(&self) -> usize {
+            self.0
+        }
+    }
+
+    fn batched_over(
+        items: Vec<MockItem>,
+        sortish: bool,
+        shuffle: bool,
+        batch_limit: usize,
+        seed: Option<u64>,
+    ) -> Batched<std::vec::IntoIter<MockItem>> {
+        items.into_iter().batched(
+            false,
+            sortish,
+            shuffle,
+            2,
+            batch_limit,
+            BatchLimitType::BatchSize,
+            seed,
+        )
+    }
+
+    #[test]
+    fn test_batched_resume_exact_without_sortish() {
+        let items: Vec<MockItem> = (0..23).map(MockItem).collect();
+        let mut first_half = batched_over(items.clone(), false, true, 4, Some(7));
+        let mut seen = Vec::new();
+        for batch in first_half.by_ref().take(2) {
+            seen.extend(batch.into_iter());
+        }
+        let state = *first_half.state_handle().lock().unwrap();
+
+        // resuming should pick up with exactly the items the original run
+        // would have yielded next, given the same rng state
+        let mut resumed = batched_over(
+            items[state.items_yielded..].to_vec(),
+            false,
+            true,
+            4,
+            Some(7),
+        );
+        resumed.resume_rng(state.rng_state.unwrap());
+        let mut rest_from_scratch = batched_over(items.clone(), false, true, 4, Some(7));
+        let uninterrupted: Vec<_> = rest_from_scratch
+            .by_ref()
+            .flatten()
+            .skip(state.items_yielded)
+            .collect();
+        let resumed_items: Vec<_> = resumed.flatten().collect();
+        assert_eq!(resumed_items, uninterrupted);
+    }
+
+    #[test]
+    fn test_batched_sortish_resume_is_exact_at_megabatch_boundary() {
+        let items: Vec<MockItem> = (0..40).map(MockItem).collect();
+        let mut iter = batched_over(items.clone(), true, false, 4, Some(3));
+        // consume one batch out of the first megabatch (batch_limit * prefetch_factor = 8)
+        let first_batch: Vec<_> = iter.next().unwrap().into_iter().collect();
+        let state = *iter.state_handle().lock().unwrap();
+        // items_yielded must lag behind what's actually been handed out so
+        // far, since the first megabatch hasn't fully drained yet
+        assert!(state.items_yielded < first_batch.len());
+        assert_eq!(state.items_yielded, 0);
+
+        // draining the rest of the megabatch should fold the lagging count
+        // in once it's fully served
+        let second_batch: Vec<_> = iter.next().unwrap().into_iter().collect();
+        let state_after_megabatch = *iter.state_handle().lock().unwrap();
+        assert_eq!(
+            state_after_megabatch.items_yielded,
+            first_batch.len() + second_batch.len()
+        );
+
+        // resuming from that boundary must reproduce the exact remaining items
+        let mut resumed = batched_over(
+            items[state_after_megabatch.items_yielded..].to_vec(),
+            true,
+            false,
+            4,
+            Some(3),
+        );
+        resumed.resume_rng(state_after_megabatch.rng_state.unwrap());
+        let resumed_items: Vec<_> = resumed.flatten().collect();
+
+        let mut uninterrupted = batched_over(items, true, false, 4, Some(3));
+        let all_items: Vec<_> = uninterrupted.by_ref().flatten().collect();
+        assert_eq!(
+            resumed_items,
+            all_items[state_after_megabatch.items_yielded..]
+        );
+    }
+
+    #[test]
+    fn test_take_packed_batch_isolates_oversized_items() {
+        let items: Vec<MockItem> = vec![3, 4, 15, 2, 8, 3].into_iter().map(MockItem).collect();
+        let mut batched = items.into_iter().batched(
+            false,
+            false,
+            false,
+            1,
+            10,
+            BatchLimitType::Packed,
+            None,
+        );
+        let batches: Vec<Batch<MockItem>> = batched.by_ref().collect();
+        // the oversized item (size 15) must end up alone in its own batch,
+        // never sharing a row with, or forcing padding on, anything else
+        let oversized_idx = batches
+            .iter()
+            .position(|b| b.items.len() == 1 && b.items[0].0 == 15)
+            .expect("oversized item should get its own batch");
+        assert_eq!(batches[oversized_idx].packed_row_sizes, Some(vec![1]));
+        // every other item must still be present across the remaining batches
+        let mut rest: Vec<usize> = batches
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| *idx != oversized_idx)
+            .flat_map(|(_, b)| b.items.iter().map(|i| i.0))
+            .collect();
+        rest.sort_unstable();
+        assert_eq!(rest, vec![2, 3, 3, 4, 8]);
+    }
+
+    fn mock_cache_item(id: u32) -> Item {
+        Item::new(
+            TextData::new(format!("line {id}"), None, None),
+            Tokenization {
+                token_ids: vec![id; (id % 5 + 1) as usize],
+                info: TokenizationInfo::Empty,
+            },
+            Label::Classification(id as i32),
+        )
+    }
+
+    #[test]
+    fn test_item_cache_round_trip() {
+        let dir = scratch_dir("item_cache");
+        let items: Vec<Item> = (0..10).map(mock_cache_item).collect();
+        let caching_iter = CachingIter::new(items.clone().into_iter(), &dir, 1).unwrap();
+        // draining to exhaustion is what triggers the atomic rename + index write
+        let written: Vec<Item> = caching_iter.collect();
+        assert_eq!(written.len(), items.len());
+
+        let cache = ItemCache::load(&dir, 1).unwrap().expect("cache should exist");
+        assert_eq!(cache.len(), items.len());
+        for (idx, original) in items.iter().enumerate() {
+            let reread = cache.get(idx).unwrap();
+            assert_eq!(reread.tokenization.token_ids, original.tokenization.token_ids);
+            assert_eq!(reread.data.original, original.data.original);
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_global_shuffle_index_shard_disjoint() {
+        let dir = scratch_dir("global_shuffle");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        fs::write(&path, (0..37).map(|i| format!("line {i}\n")).collect::<String>()).unwrap();
+
+        let index = GlobalShuffleIndex::build_or_load(vec![path], vec![None], &dir).unwrap();
+        assert_eq!(index.len(), 37);
+
+        let world_size = 3;
+        let mut union = Vec::new();
+        for rank in 0..world_size {
+            union.extend(index.shard_permutation(11, 0, rank, world_size));
+        }
+        union.sort_unstable();
+        assert_eq!(union, (0..37).collect::<Vec<_>>());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_weighted_distributed_is_deterministic_and_disjoint() {
+        let dir = scratch_dir("weighted");
+        fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.txt");
+        let b = dir.join("b.txt");
+        fs::write(&a, (0..10).map(|i| format!("a{i}\n")).collect::<String>()).unwrap();
+        fs::write(&b, (0..20).map(|i| format!("b{i}\n")).collect::<String>()).unwrap();
+        let sources = || vec![(a.clone(), None), (b.clone(), None)];
+
+        let world_size = 2;
+        let mut per_rank = Vec::new();
+        for rank in 0..world_size {
+            let mut iter = TextIterator::new_weighted_distributed(
+                sources(),
+                Some(5),
+                rank,
+                world_size,
+            )
+            .unwrap();
+            let originals: Vec<String> = std::iter::from_fn(|| iter.next())
+                .map(|r| r.unwrap().original)
+                .collect();
+            per_rank.push(originals);
+        }
+
+        // every rank must read a disjoint set of lines...
+        let rank0: std::collections::HashSet<_> = per_rank[0].iter().cloned().collect();
+        let rank1: std::collections::HashSet<_> = per_rank[1].iter().cloned().collect();
+        assert!(rank0.is_disjoint(&rank1));
+        // ...whose union is the whole corpus, with nothing read twice
+        let mut union: Vec<String> = per_rank.into_iter().flatten().collect();
+        union.sort();
+        let mut expected: Vec<String> = (0..10)
+            .map(|i| format!("a{i}"))
+            .chain((0..20).map(|i| format!("b{i}")))
+            .collect();
+        expected.sort();
+        assert_eq!(union, expected);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
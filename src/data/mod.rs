@@ -1,5 +1,6 @@
 use crate::data::loading::{
-    BatchLimitType, BatchedIterator, DataGen, PipelineIterator, TextIterationStrategy,
+    BatchLimitType, BatchedIterator, CachedIterator, CachedItemIter, DataGen, GlobalShuffleGen,
+    GlobalShuffleIndex, ItemCache, PipelineIterator, ShuffleMode, TextIterationStrategy,
 };
 use crate::data::preprocessing::{labeling, preprocessing, LabelingConfig, PreprocessingConfig};
 use crate::text::clean;
@@ -16,14 +17,14 @@ use pyo3::prelude::*;
 use pyo3::types::PyDict;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::vec::IntoIter;
 
 use self::loading::{
-    inference_data_generator_from_file, inference_data_generator_from_python,
-    text_data_generator_from_files, BufferedIterator, ItemSize, Tensorize, TensorizedIterator,
-    TextIterator,
+    fisher_yates_permutation, inference_data_generator_from_file,
+    inference_data_generator_from_python, text_data_generator_from_files, BufferedIterator,
+    ItemSize, LoaderState, Tensorize, TensorizedIterator, TextIterator,
 };
 
 pub mod loading;
@@ -306,11 +307,25 @@ impl InferenceItem {
 #[derive(Debug)]
 pub struct Batch<T> {
     items: Vec<T>,
+    // only set for BatchLimitType::Packed batches: the number of items
+    // making up each packed sequence, in order, so tensorize() can derive
+    // cu_seqlens-style item boundaries within the packed token array
+    packed_row_sizes: Option<Vec<usize>>,
 }
 
 impl<T> Batch<T> {
     pub fn new(items: Vec<T>) -> Self {
-        Batch { items }
+        Batch {
+            items,
+            packed_row_sizes: None,
+        }
+    }
+
+    pub fn new_packed(items: Vec<T>, row_sizes: Vec<usize>) -> Self {
+        Batch {
+            items,
+            packed_row_sizes: Some(row_sizes),
+        }
     }
 
     pub fn len(&self) -> usize {
@@ -415,6 +430,54 @@ fn prepare(
     })
 }
 
+/// Like [prepare], but for `BatchLimitType::Packed` batches: `tokenizations`
+/// is already grouped into `row_sizes.len()` packed rows (row-major, so the
+/// first `row_sizes[0]` tokenizations form row 0, and so on), and each row
+/// is laid out as the concatenation of its items' token ids, padded only up
+/// to the longest row actually produced. The per-row item boundaries are
+/// returned via `info["cu_seqlens"]` so a block-diagonal attention mask can
+/// be built from them downstream.
+#[inline]
+fn prepare_packed(
+    tokenizations: Vec<&Tokenization>,
+    row_sizes: &[usize],
+    pad_token_id: u32,
+) -> (Py<PyArray2<u32>>, Vec<usize>, Py<PyDict>) {
+    let num_rows = row_sizes.len();
+    let mut rows: Vec<Vec<u32>> = Vec::with_capacity(num_rows);
+    let mut lengths = Vec::with_capacity(num_rows);
+    let mut cu_seqlens: Vec<Vec<usize>> = Vec::with_capacity(num_rows);
+    let mut idx = 0;
+    for &row_size in row_sizes {
+        let mut row = Vec::new();
+        let mut boundaries = vec![0usize];
+        for tokenization in &tokenizations[idx..idx + row_size] {
+            row.extend(tokenization.token_ids.iter().copied());
+            boundaries.push(row.len());
+        }
+        lengths.push(row.len());
+        cu_seqlens.push(boundaries);
+        rows.push(row);
+        idx += row_size;
+    }
+    let max_len = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    let mut token_ids = Vec::with_capacity(num_rows * max_len);
+    for row in &rows {
+        token_ids.extend(row.iter().copied());
+        token_ids.extend(vec![pad_token_id; max_len - row.len()]);
+    }
+    Python::with_gil(|py| {
+        let d = PyDict::new(py);
+        d.set_item("cu_seqlens", cu_seqlens).unwrap();
+        let token_id_arr = Array2::from_shape_vec((num_rows, max_len), token_ids).unwrap();
+        (
+            token_id_arr.into_pyarray(py).into_py(py),
+            lengths,
+            d.into_py(py),
+        )
+    })
+}
+
 impl Tensorize for Batch<Item> {
     type Output = (
         Py<PyArray2<u32>>,
@@ -425,10 +488,21 @@ impl Tensorize for Batch<Item> {
 
     fn tensorize(&self, tokenizer: &Tokenizer) -> Self::Output {
         assert!(!self.items.is_empty());
-        let (token_id_arr, lengths, info) = prepare(
-            self.items.iter().map(|i| &i.tokenization).collect(),
-            tokenizer.pad_token_id(),
-        );
+        let (token_id_arr, lengths, info) = if let Some(row_sizes) = &self.packed_row_sizes {
+            prepare_packed(
+                self.items.iter().map(|i| &i.tokenization).collect(),
+                row_sizes,
+                tokenizer.pad_token_id(),
+            )
+        } else {
+            prepare(
+                self.items.iter().map(|i| &i.tokenization).collect(),
+                tokenizer.pad_token_id(),
+            )
+        };
+        // note: labels below are still laid out one-per-item in row-major
+        // order, not one-per-packed-row; use info["cu_seqlens"] to map them
+        // back onto the packed token array's rows
 
         let batch_size = self.len();
         let max_groups = max_groups(self.items.iter().map(|i| &i.tokenization));
@@ -684,6 +758,7 @@ impl InferenceLoader {
             .batched(
                 sort,
                 false,
+                false,
                 prefetch_factor,
                 batch_limit,
                 batch_limit_type,
@@ -838,6 +913,7 @@ struct DataLoader {
     files: Vec<String>,
     languages: Option<Vec<String>>,
     strategy: TextIterationStrategy,
+    pipeline_config: PreprocessingPipelineConfig,
     tokenizer_config: TokenizerConfig,
     num_threads: u8,
     buffer_size: usize,
@@ -851,12 +927,22 @@ struct DataLoader {
     world_size: usize,
     seed: Option<u64>,
     shuffle: bool,
+    shuffle_mode: ShuffleMode,
+    global_index: Option<Arc<GlobalShuffleIndex>>,
+    cache: bool,
+    item_cache: Option<Arc<ItemCache>>,
     prefetch_factor: usize,
     sort: bool,
+    sortish: bool,
     // the next to values will be set after each __iter__ call
     #[pyo3(get)]
     min_items: Option<usize>,
     iter: Option<Box<DataIter>>,
+    // state of the current epoch's batched iterator, shared via init_iter so
+    // it can still be read out for checkpointing once iter above is boxed
+    loader_state: Arc<Mutex<LoaderState>>,
+    // rng state to resume the shuffle buffer from on the next init_iter call
+    resume_rng_state: Option<u64>,
 }
 
 impl DataLoader {
@@ -871,8 +957,11 @@ impl DataLoader {
         batch_limit: usize,
         batch_limit_type: BatchLimitType,
         shuffle: bool,
+        shuffle_mode: ShuffleMode,
+        cache: bool,
         prefetch_factor: usize,
         sort: bool,
+        sortish: bool,
         seed: Option<u64>,
         skip: usize,
         limit: Option<usize>,
@@ -883,8 +972,26 @@ impl DataLoader {
                 "seed cannot be None if shuffle is true",
             ));
         }
+        if sortish && seed.is_none() {
+            return Err(PyTypeError::new_err(
+                "seed cannot be None if sortish is true",
+            ));
+        }
+        // the on-disk item cache is built from this rank's own (already
+        // sharded) view of the pipeline output and lives in one shared,
+        // unnamespaced directory, so with more than one rank every rank
+        // would race to write the same cache files and each epoch would
+        // silently read back only a fraction of the corpus; only allow
+        // caching in the non-distributed case until the cache is made
+        // rank-aware
+        if cache && distributed.is_some_and(|(_, world_size)| world_size > 1) {
+            return Err(PyTypeError::new_err(
+                "cache cannot be true when world_size > 1, the on-disk item \
+                cache is not yet safe to share across ranks",
+            ));
+        }
         let prefetch_factor = prefetch_factor.max(1);
-        let pipeline = Pipeline::with_tokenizer(pipeline_config, tokenizer_config.clone());
+        let pipeline = Pipeline::with_tokenizer(pipeline_config.clone(), tokenizer_config.clone());
         // handle distributed arguments
         let (rank, world_size) = distributed.unwrap_or((0, 1));
         assert!(
@@ -897,6 +1004,7 @@ impl DataLoader {
             files,
             languages,
             strategy,
+            pipeline_config,
             tokenizer_config,
             num_threads,
             buffer_size,
@@ -912,56 +1020,239 @@ impl DataLoader {
             world_size,
             seed,
             shuffle,
+            shuffle_mode,
+            global_index: None,
+            cache,
+            item_cache: None,
             prefetch_factor,
             sort,
+            sortish,
+            loader_state: Arc::new(Mutex::new(LoaderState::default())),
+            resume_rng_state: None,
         })
     }
 
+    /// The global shuffle index over all files, built on first use and
+    /// reused across epochs and resumes so only the (cheap) permutation
+    /// has to be recomputed each time.
+    fn global_index(&mut self) -> anyhow::Result<Arc<GlobalShuffleIndex>> {
+        if let Some(index) = &self.global_index {
+            return Ok(index.clone());
+        }
+        let paths = self.files.iter().map(Path::new).map(Path::to_path_buf).collect();
+        let languages = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(idx, _)| self.languages.as_ref().map(|langs| langs[idx].clone()))
+            .collect();
+        let cache_dir = Path::new(&self.files[0])
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(".tcu_shuffle_index");
+        let index = Arc::new(GlobalShuffleIndex::build_or_load(
+            paths, languages, &cache_dir,
+        )?);
+        self.global_index = Some(index.clone());
+        Ok(index)
+    }
+
+    fn cache_dir(&self) -> PathBuf {
+        Path::new(&self.files[0])
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(".tcu_item_cache")
+    }
+
+    /// The on-disk tokenized item cache for this loader's exact file set
+    /// and preprocessing/tokenizer config, built on first use and reused
+    /// across epochs and resumes. Returns `None` if `self.cache` is false
+    /// or no complete cache exists for this config yet.
+    ///
+    /// Only ever built from this rank's own view of the pipeline output, so
+    /// `DataLoader::new` rejects `cache=true` for `world_size > 1` to avoid
+    /// every rank racing to write the same unnamespaced cache directory.
+    fn item_cache(&mut self) -> anyhow::Result<Option<Arc<ItemCache>>> {
+        if !self.cache {
+            return Ok(None);
+        }
+        if let Some(cache) = &self.item_cache {
+            return Ok(Some(cache.clone()));
+        }
+        let files: Vec<PathBuf> = self.files.iter().map(PathBuf::from).collect();
+        let key = ItemCache::cache_key(&files, &self.pipeline_config, &self.tokenizer_config);
+        let cache_dir = self.cache_dir();
+        match ItemCache::load(&cache_dir, key)? {
+            Some(cache) => {
+                let cache = Arc::new(cache);
+                self.item_cache = Some(cache.clone());
+                Ok(Some(cache))
+            }
+            None => Ok(None),
+        }
+    }
+
     fn init_iter(&mut self) -> anyhow::Result<()> {
         let seed = if self.seed.is_some() {
             Some(self.seed.unwrap() + self.epoch as u64)
         } else {
             None
         };
-        let mut generators = vec![];
-        for (idx, file) in self.files.iter().enumerate() {
-            let lang = if self.languages.is_some() {
-                Some(self.languages.as_ref().unwrap()[idx].clone())
+        // on a cache hit, items can be read straight off disk in index
+        // order (or, for shuffle_mode="global", in a seeded permutation of
+        // it), skipping the text iterator, pipeline and tokenizer
+        // entirely; otherwise fall back to the normal pipeline-driven path,
+        // additionally teeing it through a cache writer if caching is on
+        let cache = self.item_cache()?;
+        let base_iter: Box<dyn Iterator<Item = Item> + Send> = if let Some(cache) = cache {
+            self.min_items = Some(
+                cache
+                    .len()
+                    .min(self.limit)
+                    .saturating_sub(self.skip)
+                    / self.world_size,
+            );
+            let cached_iter = if self.shuffle_mode == ShuffleMode::Global {
+                // mirror GlobalShuffleGen's permutation on the uncached
+                // path, so cache=true still supports shuffle_mode="global"
+                // instead of silently falling back to local buffer shuffling
+                let order = fisher_yates_permutation(
+                    cache.len(),
+                    self.seed.unwrap_or(0).wrapping_add(self.epoch as u64),
+                );
+                CachedItemIter::new_shuffled(
+                    cache,
+                    order,
+                    self.skip + self.fast_forward,
+                    self.rank,
+                    self.world_size,
+                )
             } else {
-                None
+                CachedItemIter::new(
+                    cache,
+                    self.skip + self.fast_forward,
+                    self.rank,
+                    self.world_size,
+                )
             };
-            let generator = text_data_generator_from_files(Path::new(file), None, lang)?;
-            generators.push(generator);
-        }
-
-        let text_iter = TextIterator::new(generators, self.strategy, seed)?;
-        self.min_items = Some(
-            text_iter
-                .min_len()
-                .min(self.limit)
-                .saturating_sub(self.skip)
-                / self.world_size,
+            Box::new(cached_iter.filter_map(|i| i.ok()))
+        } else {
+            let text_iter = if self.shuffle_mode == ShuffleMode::Global {
+                let index = self.global_index()?;
+                let gen: Box<dyn DataGen<Item = anyhow::Result<TextData>>> =
+                    Box::new(GlobalShuffleGen::new(
+                        index,
+                        self.seed.unwrap_or(0),
+                        self.epoch as u64,
+                        self.rank,
+                        self.world_size,
+                    ));
+                TextIterator::new(vec![gen], TextIterationStrategy::Sequential, seed)?
+            } else if self.strategy == TextIterationStrategy::Weighted {
+                // the weighted mixture's rank sharding happens inside
+                // TextIterator::new_weighted_distributed, which reads each
+                // source by absolute, seek-based position rather than
+                // through a plain sequential generator (see WeightedSource)
+                // so that gating mixture positions by rank actually makes
+                // the items read disjoint, not just the positions visited
+                let sources = self
+                    .files
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, file)| {
+                        let lang = self
+                            .languages
+                            .as_ref()
+                            .map(|langs| langs[idx].clone());
+                        (PathBuf::from(file), lang)
+                    })
+                    .collect();
+                TextIterator::new_weighted_distributed(
+                    sources,
+                    seed,
+                    self.rank,
+                    self.world_size,
+                )?
+            } else {
+                // sequential/interleaved: each per-file generator reads
+                // only this rank's share of that file's byte ranges (see
+                // text_data_generator_from_files), so no file is scanned or
+                // decoded on behalf of another rank
+                let mut generators = vec![];
+                for (idx, file) in self.files.iter().enumerate() {
+                    let lang = if self.languages.is_some() {
+                        Some(self.languages.as_ref().unwrap()[idx].clone())
+                    } else {
+                        None
+                    };
+                    let generator = text_data_generator_from_files(
+                        Path::new(file),
+                        None,
+                        lang,
+                        self.rank,
+                        self.world_size,
+                    )?;
+                    generators.push(generator);
+                }
+                TextIterator::new(generators, self.strategy, seed)?
+            };
+            self.min_items = Some(
+                text_iter
+                    .min_len()
+                    .min(self.limit)
+                    .saturating_sub(self.skip)
+                    / self.world_size,
+            );
+            // every path above already assigns each rank only its own share
+            // of items, physically (global shuffle's shard_permutation,
+            // the per-file shard-aware generators) or logically (the
+            // weighted mixture's internal rank gating in
+            // TextIterator::new_weighted_distributed), so no further
+            // rank/world_size skip + step_by is applied here
+            let piped: Box<dyn Iterator<Item = Item> + Send> = Box::new(
+                text_iter
+                    .take(self.limit)
+                    .skip(self.skip + self.fast_forward)
+                    .filter_map(|d| d.ok())
+                    .pipe(&self.pipeline, self.num_threads, seed)
+                    .filter_map(|i| i.ok()),
+            );
+            if self.cache {
+                let key = ItemCache::cache_key(
+                    &self.files.iter().map(PathBuf::from).collect::<Vec<_>>(),
+                    &self.pipeline_config,
+                    &self.tokenizer_config,
+                );
+                Box::new(piped.cached(&self.cache_dir(), key)?)
+            } else {
+                piped
+            }
+        };
+        let mut batched_iter = base_iter.batched(
+            self.sort,
+            self.sortish,
+            self.shuffle,
+            self.prefetch_factor,
+            self.batch_limit,
+            self.batch_limit_type,
+            seed,
         );
-        let batch_iter = text_iter
-            .take(self.limit)
-            .skip(self.skip + self.fast_forward + self.rank)
-            .step_by(self.world_size)
-            .filter_map(|d| d.ok())
-            .pipe(&self.pipeline, self.num_threads, seed)
-            .filter_map(|i| i.ok())
-            .batched(
-                self.sort,
-                self.shuffle,
-                self.prefetch_factor,
-                self.batch_limit,
-                self.batch_limit_type,
-                seed,
-            )
+        if let Some(rng_state) = self.resume_rng_state.take() {
+            batched_iter.resume_rng(rng_state);
+        }
+        self.loader_state = batched_iter.state_handle();
+        let batch_iter = batched_iter
             .tensorized(self.tokenizer_config.clone())
             .buffered(self.buffer_size);
         self.iter = Some(Box::new(batch_iter));
         Ok(())
     }
+
+    /// The epoch seed actually fed into the pipeline and shuffle buffer,
+    /// i.e. `seed + epoch`, or `None` if this loader was built without a seed.
+    fn epoch_seed(&self) -> Option<u64> {
+        self.seed.map(|seed| seed + self.epoch as u64)
+    }
 }
 
 #[pymethods]
@@ -975,8 +1266,11 @@ impl DataLoader {
         batch_limit = "16",
         batch_limit_type = "BatchLimitType::BatchSize",
         shuffle = "false",
+        shuffle_mode = "ShuffleMode::Local",
+        cache = "false",
         prefetch_factor = "4",
         sort = "false",
+        sortish = "false",
         seed = "None",
         skip = "0",
         limit = "None",
@@ -993,8 +1287,11 @@ impl DataLoader {
         batch_limit: usize,
         batch_limit_type: BatchLimitType,
         shuffle: bool,
+        shuffle_mode: ShuffleMode,
+        cache: bool,
         prefetch_factor: usize,
         sort: bool,
+        sortish: bool,
         seed: Option<u64>,
         skip: usize,
         limit: Option<usize>,
@@ -1022,8 +1319,11 @@ impl DataLoader {
             batch_limit,
             batch_limit_type,
             shuffle,
+            shuffle_mode,
+            cache,
             prefetch_factor,
             sort,
+            sortish,
             seed,
             skip,
             limit,
@@ -1061,6 +1361,58 @@ impl DataLoader {
     fn set_fast_forward(&mut self, num_items: usize) {
         self.fast_forward = num_items
     }
+
+    /// Captures everything needed to resume this loader at exactly the
+    /// point it is currently at: the epoch, the number of items already
+    /// yielded within that epoch (from which `fast_forward` can be
+    /// recomputed), the derived per-epoch seed, and the shuffle buffer's
+    /// RNG state, if shuffling is enabled.
+    ///
+    /// When `sortish` batching is enabled, `items_yielded` only advances in
+    /// whole-megabatch steps (see [loading::LoaderState]), so a state saved
+    /// mid-megabatch resumes from the start of that megabatch rather than
+    /// the exact last item.
+    fn state_dict(&self) -> PyResult<Py<PyDict>> {
+        let state = *self.loader_state.lock().unwrap();
+        Python::with_gil(|py| {
+            let d = PyDict::new(py);
+            d.set_item("epoch", self.epoch)?;
+            // items skipped via fast_forward (e.g. on a previous resume)
+            // were part of this epoch too
+            d.set_item("items_yielded", self.fast_forward + state.items_yielded)?;
+            d.set_item("epoch_seed", self.epoch_seed())?;
+            d.set_item("rng_state", state.rng_state)?;
+            Ok(d.into_py(py))
+        })
+    }
+
+    /// Restores a state previously returned by `state_dict()`. The next
+    /// `__iter__` call will fast forward to the exact item and, if
+    /// shuffling is enabled, continue the shuffle buffer's RNG from
+    /// exactly where it left off, rather than recomputing a fresh
+    /// coarse-grained `fast_forward` skip from scratch.
+    fn load_state_dict(&mut self, state: &PyDict) -> PyResult<()> {
+        let Some(epoch) = state.get_item("epoch") else {
+            return Err(py_required_key_error("epoch", "data loader state"));
+        };
+        self.epoch = epoch.extract()?;
+        let Some(items_yielded) = state.get_item("items_yielded") else {
+            return Err(py_required_key_error("items_yielded", "data loader state"));
+        };
+        self.fast_forward = items_yielded.extract()?;
+        self.resume_rng_state = match state.get_item("rng_state") {
+            Some(rng_state) => rng_state.extract()?,
+            None => None,
+        };
+        // the previous iterator's counts no longer apply, start from the
+        // restored position until the next init_iter
+        self.loader_state = Arc::new(Mutex::new(LoaderState {
+            items_yielded: 0,
+            rng_state: self.resume_rng_state,
+        }));
+        self.iter = None;
+        Ok(())
+    }
 }
 
 /// A submodule containing functionality for text data loading.
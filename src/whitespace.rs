@@ -1,8 +1,9 @@
 use crate::unicode::{Character, CS};
-use crate::utils::py_invalid_type_error;
+use crate::utils::{get_progress_bar, py_invalid_type_error};
 use anyhow::anyhow;
 use itertools::Itertools;
 use pyo3::prelude::*;
+use rayon::prelude::*;
 use regex::{escape, Regex};
 
 #[pyfunction(use_graphemes = "true")]
@@ -68,41 +69,193 @@ pub fn operations(
     to: &str,
     use_graphemes: bool,
 ) -> anyhow::Result<Vec<WhitespaceOperation>> {
-    let from_cs = CS::new(from, use_graphemes);
-    let to_cs = CS::new(to, use_graphemes);
-    let from_chars: Vec<Character> = from_cs.chars().collect();
-    let to_chars: Vec<Character> = to_cs.chars().collect();
-    let mut operations = vec![];
-    operations.reserve(from_chars.len().max(to_chars.len()));
-    let mut from_ptr = 0;
-    let mut to_ptr = 0;
-    while from_ptr < from_chars.len() {
-        let from_char = &from_chars[from_ptr];
-        let to_char = if to_ptr < to_chars.len() {
-            Some(&to_chars[to_ptr])
+    let from_chars: Vec<Character> = CS::new(from, use_graphemes).chars().collect();
+    let to_chars: Vec<Character> = CS::new(to, use_graphemes).chars().collect();
+
+    // anchors are the non-whitespace graphemes, which must appear in the same
+    // order in from and to since only whitespace may be added or removed
+    let from_anchors: Vec<usize> = (0..from_chars.len())
+        .filter(|&i| !from_chars[i].is_whitespace())
+        .collect();
+    let to_anchors: Vec<usize> = (0..to_chars.len())
+        .filter(|&i| !to_chars[i].is_whitespace())
+        .collect();
+    if from_anchors.len() != to_anchors.len()
+        || from_anchors
+            .iter()
+            .zip(to_anchors.iter())
+            .any(|(&fi, &ti)| from_chars[fi] != to_chars[ti])
+    {
+        return Err(anyhow!(
+            "from and to must contain the same non-whitespace characters in the same \
+            order, only whitespace may be inserted or deleted:\n\
+            from: \"{from}\"\nto  : \"{to}\"\n"
+        ));
+    }
+
+    let mut ops = vec![WhitespaceOperation::Keep; from_chars.len()];
+    // walk the gaps between consecutive anchors, plus the leading gap before
+    // the first anchor and the trailing gap after the last one
+    for gap in 0..=from_anchors.len() {
+        let from_start = if gap == 0 { 0 } else { from_anchors[gap - 1] + 1 };
+        let from_end = if gap == from_anchors.len() {
+            from_chars.len()
+        } else {
+            from_anchors[gap]
+        };
+        let to_start = if gap == 0 { 0 } else { to_anchors[gap - 1] + 1 };
+        let to_end = if gap == to_anchors.len() {
+            to_chars.len()
+        } else {
+            to_anchors[gap]
+        };
+
+        let to_wants_space = (to_start..to_end).any(|i| to_chars[i].is_whitespace());
+        let mut from_whitespace = (from_start..from_end).filter(|&i| from_chars[i].is_whitespace());
+        if to_wants_space {
+            if from_whitespace.next().is_some() {
+                // keep the first whitespace char, delete the rest of the run
+                for i in from_whitespace {
+                    ops[i] = WhitespaceOperation::Delete;
+                }
+            } else if gap < from_anchors.len() {
+                ops[from_end] = WhitespaceOperation::Insert;
+            } else {
+                // trailing gap: to wants a space after the last anchor but from
+                // has none to keep and no following anchor char to attach an
+                // Insert to, so this whitespace-only diff cannot be represented
+                // with one op per from char
+                return Err(anyhow!(
+                    "cannot represent inserting trailing whitespace, from has no \
+                    trailing character to attach the insert to:\n\
+                    from: \"{from}\"\nto  : \"{to}\"\n"
+                ));
+            }
+        } else {
+            for i in from_whitespace {
+                ops[i] = WhitespaceOperation::Delete;
+            }
+        }
+    }
+    Ok(ops)
+}
+
+/// Computes [WhitespaceOperation]s for many `from`/`to` pairs in parallel.
+/// Errors are collected across all pairs and reported together with their
+/// offending index instead of aborting on the first failure.
+#[pyfunction(use_graphemes = "true", show_progress = "false")]
+pub fn operations_batch(
+    from: Vec<&str>,
+    to: Vec<&str>,
+    use_graphemes: bool,
+    show_progress: bool,
+) -> anyhow::Result<Vec<Vec<WhitespaceOperation>>> {
+    if from.len() != to.len() {
+        return Err(anyhow!(
+            "from and to must have the same length, but got {} and {}",
+            from.len(),
+            to.len()
+        ));
+    }
+    let pb = get_progress_bar(from.len() as u64, !show_progress);
+    pb.set_message("computing whitespace operations");
+    let results: Vec<anyhow::Result<Vec<WhitespaceOperation>>> = from
+        .par_iter()
+        .zip(to.par_iter())
+        .enumerate()
+        .map(|(idx, (&f, &t))| {
+            let result = operations(f, t, use_graphemes)
+                .map_err(|e| anyhow!("error at index {idx}: {e}"));
+            pb.inc(1);
+            result
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    let errors: Vec<String> = results
+        .iter()
+        .filter_map(|r| r.as_ref().err().map(|e| e.to_string()))
+        .collect();
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "{} of {} pairs failed:\n{}",
+            errors.len(),
+            results.len(),
+            errors.join("\n")
+        ));
+    }
+    Ok(results.into_iter().map(|r| r.unwrap()).collect())
+}
+
+const OPENING_BRACKETS: [&str; 3] = ["(", "[", "{"];
+const CLOSING_BRACKETS: [&str; 3] = [")", "]", "}"];
+const PUNCTUATION: [&str; 6] = [",", ";", ":", "!", "?", "."];
+
+/// Computes the [WhitespaceOperation]s that enforce E201/E202/E203-style
+/// spacing around brackets and punctuation: no whitespace right after an
+/// opening bracket or right before a closing bracket or punctuation mark,
+/// and exactly one space after a punctuation mark unless it is followed by
+/// a closing bracket. E.g. `"hello ( world ) ,next"` normalizes to
+/// `"hello (world), next"`.
+#[pyfunction(use_graphemes = "true")]
+pub fn normalize_punctuation(s: &str, use_graphemes: bool) -> Vec<WhitespaceOperation> {
+    let cs = CS::new(s, use_graphemes);
+    let chars: Vec<Character> = cs.chars().collect();
+    let mut ops = vec![WhitespaceOperation::Keep; chars.len()];
+
+    let mut idx = 0;
+    while idx < chars.len() {
+        if !chars[idx].is_whitespace() {
+            idx += 1;
+            continue;
+        }
+        // [start, end) is a maximal run of whitespace characters
+        let start = idx;
+        let mut end = idx;
+        while end < chars.len() && chars[end].is_whitespace() {
+            end += 1;
+        }
+        let left = if start > 0 {
+            Some(chars[start - 1].str)
         } else {
             None
         };
-        if to_char.is_some() && from_char == to_char.unwrap() {
-            operations.push(WhitespaceOperation::Keep);
-            to_ptr += 1;
-        } else if to_char.is_some() && to_char.unwrap().is_whitespace() {
-            operations.push(WhitespaceOperation::Insert);
-            to_ptr += 2;
-        } else if from_char.is_whitespace() {
-            operations.push(WhitespaceOperation::Delete);
+        let right = if end < chars.len() {
+            Some(chars[end].str)
         } else {
-            return Err(anyhow!(
-                "should not happen, most likely your inputs contain multiple \
-                consecutive whitespaces, leading, or trailing whitespaces, \
-                prepare them first using the clean function:\n\
-                from: \"{from}\"\nto  : \"{to}\"\n\
-                from_char: \"{from_char}\"\nto_char  : \"{to_char:?}\"\n"
-            ));
+            None
+        };
+        let wants_none = matches!(left, Some(l) if OPENING_BRACKETS.contains(&l))
+            || matches!(right, Some(r) if CLOSING_BRACKETS.contains(&r) || PUNCTUATION.contains(&r));
+        let wants_one = right.is_some() && matches!(left, Some(l) if PUNCTUATION.contains(&l));
+        if wants_none {
+            for op in &mut ops[start..end] {
+                *op = WhitespaceOperation::Delete;
+            }
+        } else if wants_one {
+            // keep exactly one whitespace character, delete the rest
+            for op in &mut ops[start + 1..end] {
+                *op = WhitespaceOperation::Delete;
+            }
         }
-        from_ptr += 1;
+        idx = end;
     }
-    Ok(operations)
+
+    // a punctuation mark directly followed by a non-whitespace,
+    // non-closing-bracket character needs a space inserted between them
+    for idx in 0..chars.len() {
+        if !PUNCTUATION.contains(&chars[idx].str) {
+            continue;
+        }
+        let Some(next) = chars.get(idx + 1) else {
+            continue;
+        };
+        if !next.is_whitespace() && !CLOSING_BRACKETS.contains(&next.str) {
+            ops[idx + 1] = WhitespaceOperation::Insert;
+        }
+    }
+
+    ops
 }
 
 pub fn repair(
@@ -148,6 +301,53 @@ fn repair_py(
     repair(s, &operations, use_graphemes)
 }
 
+/// Applies [repair] to many `s`/`operations` pairs in parallel. Errors are
+/// collected across all pairs and reported together with their offending
+/// index instead of aborting on the first failure.
+#[pyfunction(use_graphemes = "true", show_progress = "false")]
+pub fn repair_batch(
+    s: Vec<&str>,
+    operations: Vec<Vec<WhitespaceOperation>>,
+    use_graphemes: bool,
+    show_progress: bool,
+) -> anyhow::Result<Vec<String>> {
+    if s.len() != operations.len() {
+        return Err(anyhow!(
+            "s and operations must have the same length, but got {} and {}",
+            s.len(),
+            operations.len()
+        ));
+    }
+    let pb = get_progress_bar(s.len() as u64, !show_progress);
+    pb.set_message("repairing whitespace");
+    let results: Vec<anyhow::Result<String>> = s
+        .par_iter()
+        .zip(operations.par_iter())
+        .enumerate()
+        .map(|(idx, (&s, ops))| {
+            let result =
+                repair(s, ops, use_graphemes).map_err(|e| anyhow!("error at index {idx}: {e}"));
+            pb.inc(1);
+            result
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    let errors: Vec<String> = results
+        .iter()
+        .filter_map(|r| r.as_ref().err().map(|e| e.to_string()))
+        .collect();
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "{} of {} pairs failed:\n{}",
+            errors.len(),
+            results.len(),
+            errors.join("\n")
+        ));
+    }
+    Ok(results.into_iter().map(|r| r.unwrap()).collect())
+}
+
 #[pyfunction]
 pub fn find_substring_ignoring_whitespace(
     s: &str,
@@ -164,12 +364,46 @@ pub fn find_substring_ignoring_whitespace(
     re.find(s).map_or(None, |m| Some((m.start(), m.end())))
 }
 
+/// Like [find_substring_ignoring_whitespace], but additionally returns a
+/// mapping from every non-whitespace grapheme of `substring` to its byte
+/// span in `s`, so per-character annotations on `substring` can be
+/// transferred back onto the original, whitespaced `s`.
+#[pyfunction]
+pub fn align_substring_ignoring_whitespace(
+    s: &str,
+    substring: &str,
+    use_graphemes: bool,
+) -> Option<((usize, usize), Vec<(usize, usize)>)> {
+    let cs = CS::new(substring, use_graphemes);
+    let chars: Vec<Character> = cs.chars().filter(|c| !c.is_whitespace()).collect();
+    let pattern = chars
+        .iter()
+        .map(|c| format!("({})", escape(c.str)))
+        .join(r"\s*");
+    let re = Regex::new(pattern.as_str()).expect("invalid pattern, should never happen");
+    let captures = re.captures(s)?;
+    let whole = captures.get(0)?;
+    let alignment = (1..=chars.len())
+        .map(|group| {
+            let m = captures
+                .get(group)
+                .expect("every grapheme group should have matched");
+            (m.start(), m.end())
+        })
+        .collect();
+    Some(((whole.start(), whole.end()), alignment))
+}
+
 /// A submodule containing functionality specific to handle whitespaces in text.
 pub(super) fn add_submodule(py: Python<'_>, parent_module: &PyModule) -> PyResult<()> {
     let m = PyModule::new(py, "whitespace")?;
     m.add_function(wrap_pyfunction!(find_substring_ignoring_whitespace, m)?)?;
+    m.add_function(wrap_pyfunction!(align_substring_ignoring_whitespace, m)?)?;
     m.add_function(wrap_pyfunction!(repair_py, m)?)?;
+    m.add_function(wrap_pyfunction!(repair_batch, m)?)?;
     m.add_function(wrap_pyfunction!(operations, m)?)?;
+    m.add_function(wrap_pyfunction!(operations_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(normalize_punctuation, m)?)?;
     m.add_function(wrap_pyfunction!(full, m)?)?;
     m.add_function(wrap_pyfunction!(remove, m)?)?;
     parent_module.add_submodule(m)?;
@@ -180,7 +414,9 @@ pub(super) fn add_submodule(py: Python<'_>, parent_module: &PyModule) -> PyResul
 #[cfg(test)]
 mod tests {
     use crate::whitespace::{
-        find_substring_ignoring_whitespace, full, operations, remove, repair, WhitespaceOperation,
+        align_substring_ignoring_whitespace, find_substring_ignoring_whitespace, full,
+        normalize_punctuation, operations, operations_batch, remove, repair, repair_batch,
+        WhitespaceOperation,
     };
 
     #[test]
@@ -218,6 +454,54 @@ mod tests {
                 .collect::<Vec<u8>>(),
             vec![2, 0, 2, 2, 0, 2, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 2]
         );
+        // consecutive, leading, and trailing whitespace no longer error out
+        let from = "  hello   world  ";
+        let to = "hello world";
+        assert_eq!(
+            repair(from, &operations(from, to, true).unwrap(), true).unwrap(),
+            to
+        );
+        assert!(operations("different", "words", true).is_err());
+        // inserting a trailing space cannot be represented by one op per
+        // from char, so it must error instead of silently dropping it
+        assert!(operations("hello world", "hello world ", true).is_err());
+    }
+
+    #[test]
+    fn test_operations_batch() {
+        let from = vec!["t h isis a test", "a,b", "same"];
+        let to = vec!["this is a test", "a, b", "same"];
+        let batch = operations_batch(from.clone(), to.clone(), true, false).unwrap();
+        assert_eq!(batch.len(), from.len());
+        for (f, (t, ops)) in from.iter().zip(to.iter().zip(batch.iter())) {
+            assert_eq!(&operations(f, t, true).unwrap(), ops);
+        }
+        // one malformed pair should still report which index failed
+        let err = operations_batch(vec!["this", "that"], vec!["this", "other"], true, false)
+            .unwrap_err();
+        assert!(err.to_string().contains("index 1"));
+    }
+
+    #[test]
+    fn test_normalize_punctuation() {
+        let s = "hello ( world ) ,next";
+        let ops = normalize_punctuation(s, true);
+        assert_eq!(repair(s, &ops, true).unwrap(), "hello (world), next");
+        let s = "foo(  bar  )";
+        assert_eq!(
+            repair(s, &normalize_punctuation(s, true), true).unwrap(),
+            "foo(bar)"
+        );
+        let s = "a,b;c : d ! e?f .";
+        assert_eq!(
+            repair(s, &normalize_punctuation(s, true), true).unwrap(),
+            "a, b; c: d! e? f."
+        );
+        let s = "no punctuation here";
+        assert_eq!(
+            repair(s, &normalize_punctuation(s, true), true).unwrap(),
+            s
+        );
     }
 
     #[test]
@@ -239,6 +523,21 @@ mod tests {
         assert_eq!(repair("", &vec![], true).unwrap(), "");
     }
 
+    #[test]
+    fn test_repair_batch() {
+        let s = vec!["t h isis a test", "t"];
+        let ops = vec![
+            operations(s[0], "this is a test", true).unwrap(),
+            vec![WhitespaceOperation::Delete],
+        ];
+        let batch = repair_batch(s.clone(), ops.clone(), true, false).unwrap();
+        assert_eq!(batch, vec!["this is a test", "t"]);
+        // mismatched lengths for one pair should still report its index
+        let bad_ops = vec![ops[0].clone(), vec![]];
+        let err = repair_batch(s, bad_ops, true, false).unwrap_err();
+        assert!(err.to_string().contains("index 1"));
+    }
+
     #[test]
     fn test_find_substring_ignoring_whitespace() {
         let s = "this is a test sentence";
@@ -256,4 +555,20 @@ mod tests {
         let result = find_substring_ignoring_whitespace(s, sub, true);
         assert!(result.is_some());
     }
+
+    #[test]
+    fn test_align_substring_ignoring_whitespace() {
+        let s = "this is a test sentence";
+        let sub = "  a te s\n t";
+        let (span, alignment) = align_substring_ignoring_whitespace(s, sub, true).unwrap();
+        assert_eq!(span, (8, 14));
+        assert_eq!(
+            alignment,
+            vec![(8, 9), (10, 11), (11, 12), (12, 13), (13, 14)]
+        );
+        for (start, end) in &alignment {
+            assert!(sub.contains(&s[*start..*end]));
+        }
+        assert!(align_substring_ignoring_whitespace(s, "a t??st", true).is_none());
+    }
 }